@@ -3,12 +3,12 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::fs::{File, Metadata};
 use std::hash::{Hash, Hasher};
-use std::io::{BufRead, BufReader, Read, Take};
+use std::io::{BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Take};
 use std::os::unix::fs::MetadataExt;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, TimeZone, Utc};
 use varnish::run_vtc_tests;
 use varnish::vcl::{Backend, Ctx, LogTag, StrOrBytes, VclBackend, VclResponse, VclResult};
 
@@ -34,6 +34,10 @@ mod fileserver {
             #[vcl_name] name: &str,
             path: &str,
             mime_db: Option<&str>,
+            index: Option<&str>,
+            autoindex: bool,
+            precompressed: bool,
+            mime_mode: Option<&str>,
         ) -> Result<Self, Box<dyn Error>> {
             // sanity check (note that we don't have null pointers, so path is
             // at worst empty)
@@ -52,6 +56,26 @@ mod fileserver {
                 Some(p) => Some(build_mime_dict(p)?),
             };
 
+            // name of the index file to look for in a directory, "index.html"
+            // unless the user asked for something else, or disabled it entirely
+            // with an empty string
+            let index = match index {
+                None => Some("index.html".to_string()),
+                Some("") => None,
+                Some(s) => Some(s.to_string()),
+            };
+
+            // how hard should we try to find a content-type: stick to the
+            // extension, fall back to sniffing, or always sniff first
+            let mime_mode = match mime_mode {
+                None | Some("extension") => MimeMode::ExtensionOnly,
+                Some("sniff-fallback") => MimeMode::SniffFallback,
+                Some("sniff-always") => MimeMode::SniffAlways,
+                Some(other) => {
+                    return Err(format!("fileserver: unknown mime_mode '{other}', expected one of extension, sniff-fallback, sniff-always").into());
+                }
+            };
+
             let backend = Backend::new(
                 ctx,
                 "fileserver",
@@ -59,6 +83,10 @@ mod fileserver {
                 FileBackend {
                     mimes,
                     path: path.to_string(),
+                    index,
+                    autoindex,
+                    precompressed,
+                    mime_mode,
                 },
                 false,
             )?;
@@ -82,6 +110,20 @@ struct root {
 struct FileBackend {
     path: String,                           // top directory of our backend
     mimes: Option<HashMap<String, String>>, // a hashmap linking extensions to maps (optional)
+    index: Option<String>,                  // name of the index file to serve for a directory
+    autoindex: bool,                        // generate a listing when there's no index file
+    precompressed: bool,                    // serve .br/.gz siblings when the client accepts them
+    mime_mode: MimeMode,                    // how to resolve a file's content-type
+}
+
+// how we pick a content-type for a response
+enum MimeMode {
+    // only ever use the extension against the mime database
+    ExtensionOnly,
+    // fall back to sniffing the file's magic bytes if the extension is unknown
+    SniffFallback,
+    // always sniff first, and only fall back to the extension if that fails
+    SniffAlways,
 }
 
 // silly helper until varnish-rs provides something more ergonomic
@@ -93,6 +135,62 @@ fn sob_helper(sob: StrOrBytes) -> &str {
     }
 }
 
+// does this `Accept-Encoding` header value accept `enc` (and not explicitly
+// disable it with a `q=0`)?
+fn accepts_encoding(header: &str, enc: &str) -> bool {
+    header.split(',').any(|tok| {
+        let mut parts = tok.split(';');
+        let Some(name) = parts.next().map(str::trim) else {
+            return false;
+        };
+        if !name.eq_ignore_ascii_case(enc) {
+            return false;
+        }
+        !parts.next().is_some_and(|q| q.trim().eq_ignore_ascii_case("q=0"))
+    })
+}
+
+// read the first few bytes of `f` to guess a content-type from its magic
+// bytes, then seek back to the start so the body we send is unaffected
+fn sniff_file(f: &mut File) -> Option<&'static str> {
+    let mut buf = [0u8; 512];
+    let n = f.read(&mut buf).ok()?;
+    f.seek(SeekFrom::Start(0)).ok()?;
+    sniff_mime(&buf[..n])
+}
+
+// recognize a handful of common file signatures, with a UTF-8 heuristic as
+// a last resort; returns None when nothing matches
+fn sniff_mime(buf: &[u8]) -> Option<&'static str> {
+    if buf.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some("image/png")
+    } else if buf.starts_with(b"\xff\xd8\xff") {
+        Some("image/jpeg")
+    } else if buf.starts_with(b"GIF87a") || buf.starts_with(b"GIF89a") {
+        Some("image/gif")
+    } else if buf.starts_with(b"%PDF-") {
+        Some("application/pdf")
+    } else if buf.starts_with(b"\x1f\x8b") {
+        Some("application/gzip")
+    } else if buf.len() >= 12 && &buf[0..4] == b"RIFF" && &buf[8..12] == b"WEBP" {
+        Some("image/webp")
+    } else {
+        // buf is a prefix of the file, so a multi-byte sequence may be cut
+        // off at the end; only require everything up to that point to decode
+        let valid_up_to = match std::str::from_utf8(buf) {
+            Ok(_) => buf.len(),
+            // error_len() is None only when the input ends mid-sequence
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => 0,
+        };
+        if valid_up_to > 0 {
+            Some("text/plain")
+        } else {
+            None
+        }
+    }
+}
+
 impl VclBackend<FileTransfer> for FileBackend {
     fn get_response(&self, ctx: &mut Ctx) -> VclResult<Option<FileTransfer>> {
         // we know that bereq and bereq_url, so we can just unwrap the options
@@ -100,7 +198,7 @@ impl VclBackend<FileTransfer> for FileBackend {
         let bereq_url = sob_helper(bereq.url().unwrap());
 
         // combine root and url into something that's hopefully safe
-        let path = assemble_file_path(&self.path, bereq_url);
+        let mut path = assemble_file_path(&self.path, bereq_url);
         ctx.log(LogTag::Debug, format!("fileserver: file on disk: {path:?}"));
 
         // reset the bereq lifetime, otherwise we couldn't use ctx in the line above
@@ -110,26 +208,100 @@ impl VclBackend<FileTransfer> for FileBackend {
         // let's start building our response
         let beresp = ctx.http_beresp.as_mut().unwrap();
 
+        // a directory isn't something we can File::open() and stream, so try
+        // to resolve it to an index file first, and fall back to a generated
+        // listing if we're allowed to
+        if path.is_dir() {
+            // whatever we end up serving for this directory (index file or
+            // listing) has links/assets relative to it, so the browser needs
+            // the trailing slash to resolve them here and not against the
+            // parent; send it there before resolving anything else
+            if !bereq_url.ends_with('/') {
+                beresp.set_proto("HTTP/1.1")?;
+                beresp.set_status(301);
+                beresp.set_header("location", &format!("{bereq_url}/"))?;
+                return Ok(None);
+            }
+            let index_path = self
+                .index
+                .as_deref()
+                .map(|name| path.join(name))
+                .filter(|p| p.is_file());
+            if let Some(index_path) = index_path {
+                path = index_path;
+            } else if self.autoindex {
+                let method = bereq.method().map(sob_helper);
+                if method != Some("HEAD") && method != Some("GET") {
+                    beresp.set_status(405);
+                    return Ok(None);
+                }
+                let body = render_autoindex(&path, bereq_url);
+                beresp.set_proto("HTTP/1.1")?;
+                beresp.set_status(200);
+                beresp.set_header("content-length", &format!("{}", body.len()))?;
+                beresp.set_header("content-type", "text/html; charset=utf-8")?;
+                return Ok(match method {
+                    Some("GET") => Some(FileTransfer::Buf(Cursor::new(body))),
+                    _ => None,
+                });
+            }
+            // neither an index nor autoindex: the directory goes down the same
+            // path as a file, as it always has; File::open() below succeeds on
+            // it, and it's reading the body of a GET that fails
+        }
+
+        // if the client can take a precompressed variant and one exists right
+        // next to the file, serve that instead and avoid paying for the
+        // compression ourselves; br wins over gzip when both are on the table
+        let mut open_path = path.clone();
+        let mut content_encoding = None;
+        if self.precompressed {
+            if let Some(ae) = bereq.header("accept-encoding").map(sob_helper) {
+                for (suffix, enc) in [("br", "br"), ("gz", "gzip")] {
+                    if !accepts_encoding(ae, enc) {
+                        continue;
+                    }
+                    let candidate = PathBuf::from(format!("{}.{suffix}", path.display()));
+                    if candidate.is_file() {
+                        open_path = candidate;
+                        content_encoding = Some(enc);
+                        break;
+                    }
+                }
+            }
+        }
+
         // open the file and get some metadata
-        let f = File::open(&path).map_err(|e| e.to_string())?;
+        let mut f = File::open(&open_path).map_err(|e| e.to_string())?;
         let metadata: Metadata = f.metadata().map_err(|e| e.to_string())?;
         let cl = metadata.len();
         let modified: DateTime<Utc> = DateTime::from(metadata.modified().unwrap());
         let etag = generate_etag(&metadata);
 
-        // can we avoid sending a body?
-        let mut is_304 = false;
-        if let Some(inm) = bereq.header("if-none-match").map(sob_helper) {
-            if inm == etag || (inm.starts_with("W/") && inm[2..] == etag) {
-                is_304 = true;
-            }
-        } else if let Some(ims) = bereq.header("if-modified-since").map(sob_helper) {
-            if let Ok(t) = DateTime::parse_from_rfc2822(ims) {
-                if t > modified {
-                    is_304 = true;
-                }
+        // figure out the content-type, consulting the mime database by
+        // extension and/or sniffing the file's magic bytes, depending on mode;
+        // this has to happen before `f` gets consumed by the transfer below.
+        // if we're serving a precompressed variant, the bytes on the wire
+        // aren't representative of the real content, so only trust the
+        // (uncompressed) extension in that case
+        let ext_type = path
+            .extension()
+            .and_then(|ext| Some(self.mimes.as_ref()?.get(ext.to_string_lossy().as_ref())?));
+        let content_type = if cl == 0 {
+            None
+        } else if content_encoding.is_some() {
+            ext_type.cloned()
+        } else {
+            match self.mime_mode {
+                MimeMode::ExtensionOnly => ext_type.cloned(),
+                MimeMode::SniffFallback => ext_type
+                    .cloned()
+                    .or_else(|| sniff_file(&mut f).map(str::to_string)),
+                MimeMode::SniffAlways => sniff_file(&mut f)
+                    .map(str::to_string)
+                    .or_else(|| ext_type.cloned()),
             }
-        }
+        };
 
         beresp.set_proto("HTTP/1.1")?;
         let mut transfer = None;
@@ -138,54 +310,180 @@ impl VclBackend<FileTransfer> for FileBackend {
             // we are fairly strict in what method we accept
             beresp.set_status(405);
             return Ok(None);
-        } else if is_304 {
-            // 304 will save us some bandwidth
-            beresp.set_status(304);
-        } else {
-            // "normal" request, if it's a HEAD to save a bunch of work, but if
-            // it's a GET we need to add the VFP to the pipeline
-            // and add a BackendResp to the priv1 field
-            beresp.set_status(200);
-            if method == Some("GET") {
-                transfer = Some(FileTransfer {
-                    // prevent reading more than expected
-                    reader: BufReader::new(f).take(cl),
+        }
+
+        // evaluate the conditional-request headers, per RFC 7232's precedence
+        let header = |h: &'static str| bereq.header(h).map(sob_helper);
+        match evaluate_conditional(header, &etag, modified) {
+            Conditional::PreconditionFailed => {
+                beresp.set_status(412);
+                return Ok(None);
+            }
+            Conditional::NotModified => {
+                // 304 will save us some bandwidth
+                beresp.set_status(304);
+            }
+            Conditional::Proceed => {
+                // do we have a Range to honor? if-range can disqualify it, in which
+                // case we fall back to a normal, full response
+                let range_header = bereq.header("range").map(sob_helper);
+                let honor_range = range_header.is_some_and(|_| {
+                    bereq
+                        .header("if-range")
+                        .map(sob_helper)
+                        .is_none_or(|ir| if_range_matches(ir, &etag, modified))
                 });
+                let range = range_header
+                    .filter(|_| honor_range)
+                    .map(|h| parse_range(h, cl));
+
+                match range {
+                    Some(RangeCheck::Unsatisfiable) => {
+                        beresp.set_status(416);
+                        beresp.set_header("content-range", &format!("bytes */{cl}"))?;
+                        beresp.set_header("accept-ranges", "bytes")?;
+                        return Ok(None);
+                    }
+                    Some(RangeCheck::Satisfiable(start, end)) => {
+                        let range_len = end - start + 1;
+                        beresp.set_status(206);
+                        beresp.set_header("content-range", &format!("bytes {start}-{end}/{cl}"))?;
+                        beresp.set_header("content-length", &format!("{range_len}"))?;
+                        if method == Some("GET") {
+                            let mut reader = BufReader::new(f);
+                            reader
+                                .seek(SeekFrom::Start(start))
+                                .map_err(|e| e.to_string())?;
+                            transfer = Some(FileTransfer::File(reader.take(range_len)));
+                        }
+                    }
+                    None | Some(RangeCheck::Ignore) => {
+                        // "normal" request, if it's a HEAD to save a bunch of work, but if
+                        // it's a GET we need to add the VFP to the pipeline
+                        // and add a BackendResp to the priv1 field
+                        beresp.set_status(200);
+                        beresp.set_header("content-length", &format!("{cl}"))?;
+                        if method == Some("GET") {
+                            // prevent reading more than expected
+                            transfer = Some(FileTransfer::File(BufReader::new(f).take(cl)));
+                        }
+                    }
+                }
+                beresp.set_header("accept-ranges", "bytes")?;
             }
         }
 
         // set all the headers we can, including the content-type if we can
-        beresp.set_header("content-length", &format!("{cl}"))?;
         beresp.set_header("etag", &etag)?;
         beresp.set_header(
             "last-modified",
             &modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
         )?;
+        if let Some(enc) = content_encoding {
+            beresp.set_header("content-encoding", enc)?;
+        }
+        if self.precompressed {
+            // every representation of this URL (compressed or not) needs to
+            // declare the same Vary, or Varnish may cache one and hand it
+            // out regardless of a later client's accept-encoding
+            beresp.set_header("vary", "accept-encoding")?;
+        }
 
-        // we only care about content-type if there's content
-        if cl > 0 {
-            // we need both and extension and a mime database
-            if let (Some(ext), Some(h)) = (path.extension(), self.mimes.as_ref()) {
-                if let Some(ct) = h.get(ext.to_string_lossy().as_ref()) {
-                    beresp.set_header("content-type", ct)?;
-                }
-            }
+        if let Some(ct) = content_type {
+            beresp.set_header("content-type", &ct)?;
         }
         Ok(transfer)
     }
 }
 
-struct FileTransfer {
-    reader: Take<BufReader<File>>,
+enum FileTransfer {
+    // streamed straight off disk, possibly truncated to a byte range
+    File(Take<BufReader<File>>),
+    // generated in memory, e.g. a directory listing
+    Buf(Cursor<Vec<u8>>),
 }
 
 impl VclResponse for FileTransfer {
     fn read(&mut self, buf: &mut [u8]) -> VclResult<usize> {
-        self.reader.read(buf).map_err(|e| e.to_string().into())
+        match self {
+            FileTransfer::File(reader) => reader.read(buf),
+            FileTransfer::Buf(cursor) => cursor.read(buf),
+        }
+        .map_err(|e| e.to_string().into())
     }
     fn len(&self) -> Option<usize> {
-        Some(usize::try_from(self.reader.limit()).unwrap())
+        match self {
+            FileTransfer::File(reader) => Some(usize::try_from(reader.limit()).unwrap()),
+            FileTransfer::Buf(cursor) => {
+                let remaining = cursor.get_ref().len() as u64 - cursor.position();
+                Some(usize::try_from(remaining).unwrap())
+            }
+        }
+    }
+}
+
+// render a simple HTML directory listing for `dir`, with links relative to
+// `bereq_url` so the browser resolves them against the current request
+fn render_autoindex(dir: &Path, bereq_url: &str) -> Vec<u8> {
+    let title = html_escape(bereq_url);
+    let mut body = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n<body>\n<h1>Index of {title}</h1>\n<ul>\n"
+    );
+
+    let mut entries: Vec<_> = std::fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .collect();
+    entries.sort_by_key(std::fs::DirEntry::file_name);
+
+    for entry in entries {
+        let Ok(metadata) = entry.metadata() else {
+            continue;
+        };
+        let mut name = entry.file_name().to_string_lossy().into_owned();
+        let href = percent_encode(&name);
+        if metadata.is_dir() {
+            name.push('/');
+        }
+        let size = if metadata.is_dir() {
+            "-".to_string()
+        } else {
+            metadata.len().to_string()
+        };
+        let modified: DateTime<Utc> = metadata
+            .modified()
+            .map(DateTime::from)
+            .unwrap_or_else(|_| Utc.timestamp_opt(0, 0).unwrap());
+        body.push_str(&format!(
+            "<li><a href=\"{href}\">{}</a> {size} {}</li>\n",
+            html_escape(&name),
+            modified.format("%Y-%m-%d %H:%M:%S"),
+        ));
     }
+    body.push_str("</ul>\n</body>\n</html>\n");
+    body.into_bytes()
+}
+
+// percent-encode everything but the RFC 3986 "unreserved" characters
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char);
+            }
+            _ => out.push_str(&format!("%{b:02X}")),
+        }
+    }
+    out
+}
+
+fn html_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 // reads a mime database into a hashmap, if we can
@@ -252,6 +550,144 @@ fn assemble_file_path(root_path: &str, url: &str) -> PathBuf {
     PathBuf::from(complete_path)
 }
 
+// outcome of parsing a `Range` header against a known content length
+#[derive(Debug, PartialEq, Eq)]
+enum RangeCheck {
+    // the range is well-formed and applies to at least one byte
+    Satisfiable(u64, u64),
+    // the range is well-formed but doesn't apply to anything in the file
+    Unsatisfiable,
+    // we don't understand the range (multiple ranges, garbage, ...) and
+    // should just serve the full body instead
+    Ignore,
+}
+
+// parse a `Range: bytes=...` value, only handling a single range as described
+// in RFC 7233: `start-end`, `start-` or `-suffix_len`
+fn parse_range(range: &str, len: u64) -> RangeCheck {
+    let Some(spec) = range.strip_prefix("bytes=") else {
+        return RangeCheck::Ignore;
+    };
+    // we don't support multiple ranges, fall back to a full response
+    if spec.contains(',') {
+        return RangeCheck::Ignore;
+    }
+
+    if let Some(suffix) = spec.strip_prefix('-') {
+        return match suffix.parse::<u64>() {
+            Ok(0) => RangeCheck::Unsatisfiable,
+            Ok(n) => {
+                let start = len.saturating_sub(n);
+                if start >= len {
+                    RangeCheck::Unsatisfiable
+                } else {
+                    RangeCheck::Satisfiable(start, len - 1)
+                }
+            }
+            Err(_) => RangeCheck::Ignore,
+        };
+    }
+
+    let Some((start_str, end_str)) = spec.split_once('-') else {
+        return RangeCheck::Ignore;
+    };
+    let Ok(start) = start_str.parse::<u64>() else {
+        return RangeCheck::Ignore;
+    };
+    if start >= len {
+        return RangeCheck::Unsatisfiable;
+    }
+    if end_str.is_empty() {
+        return RangeCheck::Satisfiable(start, len - 1);
+    }
+    match end_str.parse::<u64>() {
+        Ok(end) if end >= start => RangeCheck::Satisfiable(start, end.min(len - 1)),
+        Ok(_) => RangeCheck::Unsatisfiable,
+        Err(_) => RangeCheck::Ignore,
+    }
+}
+
+// outcome of evaluating the RFC 7232 conditional-request headers against
+// the current representation of the file
+#[derive(Debug, PartialEq, Eq)]
+enum Conditional {
+    Proceed,
+    NotModified,
+    PreconditionFailed,
+}
+
+// evaluate If-Match, If-Unmodified-Since, If-None-Match and If-Modified-Since
+// in the precedence order mandated by RFC 7232 section 6. `header` looks up
+// a request header by (lowercase) name. We only ever get here for GET/HEAD
+// (anything else is rejected with a 405 earlier), so If-None-Match always
+// resolves to 304, never the 412 that RFC 7232 reserves for other methods.
+fn evaluate_conditional<'a>(
+    header: impl Fn(&'static str) -> Option<&'a str>,
+    etag: &str,
+    modified: DateTime<Utc>,
+) -> Conditional {
+    // If-Match: a strong comparison, weak etags never match
+    if let Some(im) = header("if-match") {
+        if !etag_list_matches(im, etag, false) {
+            return Conditional::PreconditionFailed;
+        }
+    } else if let Some(ius) = header("if-unmodified-since") {
+        // If-Unmodified-Since only applies when there was no If-Match
+        if let Some(d) = parse_http_date(ius) {
+            if modified.timestamp() > d.timestamp() {
+                return Conditional::PreconditionFailed;
+            }
+        }
+    }
+
+    if let Some(inm) = header("if-none-match") {
+        // If-None-Match allows weak comparison
+        if etag_list_matches(inm, etag, true) {
+            return Conditional::NotModified;
+        }
+    } else if let Some(ims) = header("if-modified-since") {
+        // only consulted when there was no If-None-Match
+        if let Some(d) = parse_http_date(ims) {
+            if modified.timestamp() <= d.timestamp() {
+                return Conditional::NotModified;
+            }
+        }
+    }
+
+    Conditional::Proceed
+}
+
+// does a comma-separated etag list (or `*`) contain `etag`? `weak_ok`
+// controls whether `W/`-prefixed (weak) entries are allowed to match
+fn etag_list_matches(list: &str, etag: &str, weak_ok: bool) -> bool {
+    if list.trim() == "*" {
+        return true;
+    }
+    list.split(',').map(str::trim).any(|tok| match tok.strip_prefix("W/") {
+        Some(rest) => weak_ok && rest == etag,
+        None => tok == etag,
+    })
+}
+
+// is an `If-Range` validator still good for the current version of the file?
+// it can either be a strong etag or a `last-modified`-style date
+fn if_range_matches(if_range: &str, etag: &str, modified: DateTime<Utc>) -> bool {
+    if if_range == etag {
+        return true;
+    }
+    match parse_http_date(if_range) {
+        Some(d) => d.timestamp() == modified.timestamp(),
+        None => false,
+    }
+}
+
+// parse the RFC 1123 date format we also use to emit `last-modified`
+fn parse_http_date(s: &str) -> Option<DateTime<Utc>> {
+    chrono::NaiveDateTime::parse_from_str(s, "%a, %d %b %Y %H:%M:%S GMT")
+        .ok()
+        .map(|naive| Utc.from_utc_datetime(&naive))
+}
+
 fn generate_etag(metadata: &Metadata) -> String {
     #[derive(Hash)]
     struct ShortMd {
@@ -321,4 +757,324 @@ mod tests {
         assert_eq!(h["T3"], "type3");
         assert_eq!(h.get("t2"), None);
     }
+
+    use super::{if_range_matches, parse_http_date, parse_range, RangeCheck};
+
+    #[test]
+    fn range_start_end() {
+        assert_eq!(parse_range("bytes=0-99", 1000), RangeCheck::Satisfiable(0, 99));
+    }
+
+    #[test]
+    fn range_start_only() {
+        assert_eq!(parse_range("bytes=500-", 1000), RangeCheck::Satisfiable(500, 999));
+    }
+
+    #[test]
+    fn range_suffix() {
+        assert_eq!(parse_range("bytes=-100", 1000), RangeCheck::Satisfiable(900, 999));
+    }
+
+    #[test]
+    fn range_suffix_longer_than_file() {
+        assert_eq!(parse_range("bytes=-10000", 1000), RangeCheck::Satisfiable(0, 999));
+    }
+
+    #[test]
+    fn range_suffix_zero() {
+        assert_eq!(parse_range("bytes=-0", 1000), RangeCheck::Unsatisfiable);
+    }
+
+    #[test]
+    fn range_end_clamped() {
+        assert_eq!(parse_range("bytes=900-10000", 1000), RangeCheck::Satisfiable(900, 999));
+    }
+
+    #[test]
+    fn range_start_past_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), RangeCheck::Unsatisfiable);
+    }
+
+    #[test]
+    fn range_end_before_start() {
+        assert_eq!(parse_range("bytes=500-100", 1000), RangeCheck::Unsatisfiable);
+    }
+
+    #[test]
+    fn range_multiple_ranges_ignored() {
+        assert_eq!(parse_range("bytes=0-10,20-30", 1000), RangeCheck::Ignore);
+    }
+
+    #[test]
+    fn range_garbage_ignored() {
+        assert_eq!(parse_range("bytes=abc", 1000), RangeCheck::Ignore);
+        assert_eq!(parse_range("items=0-10", 1000), RangeCheck::Ignore);
+    }
+
+    #[test]
+    fn http_date_roundtrip() {
+        let d = parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT").unwrap();
+        assert_eq!(d.format("%a, %d %b %Y %H:%M:%S GMT").to_string(), "Sun, 06 Nov 1994 08:49:37 GMT");
+    }
+
+    #[test]
+    fn http_date_invalid() {
+        assert!(parse_http_date("not a date").is_none());
+    }
+
+    use chrono::TimeZone;
+
+    #[test]
+    fn if_range_etag_match() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        assert!(if_range_matches("\"abc\"", "\"abc\"", modified));
+    }
+
+    #[test]
+    fn if_range_etag_mismatch() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        assert!(!if_range_matches("\"abc\"", "\"def\"", modified));
+    }
+
+    #[test]
+    fn if_range_date_match() {
+        let modified = chrono::Utc.timestamp_opt(784111777, 0).unwrap();
+        assert!(if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", "\"abc\"", modified));
+    }
+
+    #[test]
+    fn if_range_date_mismatch() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        assert!(!if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT", "\"abc\"", modified));
+    }
+
+    use super::percent_encode;
+
+    #[test]
+    fn percent_encode_unreserved_untouched() {
+        assert_eq!(percent_encode("abcXYZ012-._~"), "abcXYZ012-._~");
+    }
+
+    #[test]
+    fn percent_encode_space_and_special() {
+        assert_eq!(percent_encode("a b/c?d"), "a%20b%2Fc%3Fd");
+    }
+
+    #[test]
+    fn percent_encode_utf8() {
+        assert_eq!(percent_encode("é"), "%C3%A9");
+    }
+
+    use super::accepts_encoding;
+
+    #[test]
+    fn accepts_encoding_simple() {
+        assert!(accepts_encoding("gzip, br", "gzip"));
+        assert!(accepts_encoding("gzip, br", "br"));
+    }
+
+    #[test]
+    fn accepts_encoding_absent() {
+        assert!(!accepts_encoding("gzip", "br"));
+    }
+
+    #[test]
+    fn accepts_encoding_case_insensitive() {
+        assert!(accepts_encoding("GZIP", "gzip"));
+    }
+
+    #[test]
+    fn accepts_encoding_q_zero_disables() {
+        assert!(!accepts_encoding("gzip;q=0, br", "gzip"));
+    }
+
+    #[test]
+    fn accepts_encoding_nonzero_q_still_accepted() {
+        assert!(accepts_encoding("gzip;q=0.5", "gzip"));
+    }
+
+    use super::sniff_mime;
+
+    #[test]
+    fn sniff_mime_png() {
+        assert_eq!(sniff_mime(b"\x89PNG\r\n\x1a\n..."), Some("image/png"));
+    }
+
+    #[test]
+    fn sniff_mime_jpeg() {
+        assert_eq!(sniff_mime(b"\xff\xd8\xff..."), Some("image/jpeg"));
+    }
+
+    #[test]
+    fn sniff_mime_gif() {
+        assert_eq!(sniff_mime(b"GIF89a..."), Some("image/gif"));
+    }
+
+    #[test]
+    fn sniff_mime_pdf() {
+        assert_eq!(sniff_mime(b"%PDF-1.4..."), Some("application/pdf"));
+    }
+
+    #[test]
+    fn sniff_mime_gzip() {
+        assert_eq!(sniff_mime(b"\x1f\x8b..."), Some("application/gzip"));
+    }
+
+    #[test]
+    fn sniff_mime_webp() {
+        assert_eq!(sniff_mime(b"RIFF\0\0\0\0WEBP..."), Some("image/webp"));
+    }
+
+    #[test]
+    fn sniff_mime_text_fallback() {
+        assert_eq!(sniff_mime(b"hello, world"), Some("text/plain"));
+    }
+
+    #[test]
+    fn sniff_mime_unrecognized_binary() {
+        assert_eq!(sniff_mime(b"\xff\xfe\x00\x01"), None);
+    }
+
+    use super::{etag_list_matches, evaluate_conditional, Conditional};
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> impl Fn(&'static str) -> Option<&'static str> + '_ {
+        move |name| pairs.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+    }
+
+    #[test]
+    fn conditional_no_headers_proceeds() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        assert_eq!(evaluate_conditional(headers(&[]), "\"abc\"", modified), Conditional::Proceed);
+    }
+
+    #[test]
+    fn conditional_if_match_mismatch_fails() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        let h = headers(&[("if-match", "\"other\"")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::PreconditionFailed);
+    }
+
+    #[test]
+    fn conditional_if_match_match_proceeds() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        let h = headers(&[("if-match", "\"abc\"")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::Proceed);
+    }
+
+    #[test]
+    fn conditional_if_none_match_returns_not_modified() {
+        let modified = chrono::Utc.timestamp_opt(0, 0).unwrap();
+        let h = headers(&[("if-none-match", "\"abc\"")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::NotModified);
+    }
+
+    #[test]
+    fn conditional_if_unmodified_since_fails_when_modified_after() {
+        let modified = chrono::Utc.timestamp_opt(784111778, 0).unwrap();
+        let h = headers(&[("if-unmodified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::PreconditionFailed);
+    }
+
+    #[test]
+    fn conditional_if_match_takes_precedence_over_if_unmodified_since() {
+        let modified = chrono::Utc.timestamp_opt(784111778, 0).unwrap();
+        let h = headers(&[("if-match", "\"abc\""), ("if-unmodified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::Proceed);
+    }
+
+    #[test]
+    fn conditional_if_modified_since_not_modified() {
+        let modified = chrono::Utc.timestamp_opt(784111777, 0).unwrap();
+        let h = headers(&[("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::NotModified);
+    }
+
+    #[test]
+    fn conditional_if_none_match_takes_precedence_over_if_modified_since() {
+        let modified = chrono::Utc.timestamp_opt(784111777, 0).unwrap();
+        let h = headers(&[("if-none-match", "\"other\""), ("if-modified-since", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(evaluate_conditional(h, "\"abc\"", modified), Conditional::Proceed);
+    }
+
+    #[test]
+    fn etag_list_wildcard() {
+        assert!(etag_list_matches("*", "\"abc\"", false));
+    }
+
+    #[test]
+    fn etag_list_strong_match() {
+        assert!(etag_list_matches("\"abc\", \"def\"", "\"def\"", false));
+    }
+
+    #[test]
+    fn etag_list_weak_match_allowed() {
+        assert!(etag_list_matches("W/\"abc\"", "\"abc\"", true));
+    }
+
+    #[test]
+    fn etag_list_weak_match_disallowed() {
+        assert!(!etag_list_matches("W/\"abc\"", "\"abc\"", false));
+    }
+
+    #[test]
+    fn etag_list_no_match() {
+        assert!(!etag_list_matches("\"abc\"", "\"def\"", true));
+    }
+
+    use super::html_escape;
+
+    #[test]
+    fn html_escape_special_chars() {
+        assert_eq!(html_escape("<a href=\"x\">Tom & Jerry</a>"), "&lt;a href=&quot;x&quot;&gt;Tom &amp; Jerry&lt;/a&gt;");
+    }
+
+    #[test]
+    fn html_escape_plain_text_untouched() {
+        assert_eq!(html_escape("hello, world"), "hello, world");
+    }
+
+    use super::render_autoindex;
+
+    // a scratch directory that cleans itself up on drop, so tests can
+    // exercise render_autoindex against a real listing
+    struct ScratchDir(PathBuf);
+
+    impl ScratchDir {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("vmod-fileserver-test-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            ScratchDir(dir)
+        }
+    }
+
+    impl Drop for ScratchDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn render_autoindex_lists_entries() {
+        let scratch = ScratchDir::new("autoindex");
+        std::fs::write(scratch.0.join("b.txt"), b"hello").unwrap();
+        std::fs::create_dir(scratch.0.join("a-dir")).unwrap();
+
+        let body = String::from_utf8(render_autoindex(&scratch.0, "/listing")).unwrap();
+
+        assert!(body.contains("Index of /listing"));
+        // entries are sorted by file name, so the directory comes first
+        let dir_pos = body.find("a-dir/").unwrap();
+        let file_pos = body.find("b.txt").unwrap();
+        assert!(dir_pos < file_pos);
+        assert!(body.contains("href=\"a-dir\">a-dir/</a> -"));
+        assert!(body.contains("href=\"b.txt\">b.txt</a> 5"));
+    }
+
+    #[test]
+    fn render_autoindex_escapes_url_in_title() {
+        let scratch = ScratchDir::new("autoindex-escape");
+        let body = String::from_utf8(render_autoindex(&scratch.0, "/a&b")).unwrap();
+        assert!(body.contains("Index of /a&amp;b"));
+    }
 }